@@ -44,9 +44,22 @@ struct Config {
     /// Scrapes each target while starting up. Useful to test your config, don't use in production.
     #[serde(default)]
     scrape_on_startup: bool,
+    /// When present, `/metrics` is served over HTTPS instead of plain HTTP.
+    #[serde(default)]
+    tls: Option<Tls>,
     targets: Arc<Vec<Target>>,
 }
 
+/// PEM-encoded certificate and key used to serve `/metrics` over HTTPS.
+#[derive(Deserialize)]
+#[cfg_attr(feature = "generate-schema", derive(JsonSchema))]
+struct Tls {
+    /// Path to the PEM-encoded certificate chain.
+    cert_path: String,
+    /// Path to the PEM-encoded private key.
+    key_path: String,
+}
+
 fn default_log_level() -> String {
     "info".into()
 }
@@ -64,12 +77,120 @@ struct Target {
     /// Additional headers. User-Agent is set by default.
     #[serde(default)]
     headers: HashMap<String, String>,
+    /// Optional authentication, applied before any custom headers.
+    #[serde(default)]
+    auth: Option<Auth>,
     /// When the job should run. Supported formats: [english-to-cron](https://github.com/kaplanelad/english-to-cron#full-list-of-supported-english-patterns), [croner](https://github.com/Hexagon/croner-rust#pattern)
     cron: String,
+    /// Retry behaviour for transient fetch failures. When omitted, each scrape is attempted exactly once.
+    #[serde(default)]
+    retry: Option<Retry>,
+    /// Emit a warning when a successful scrape (fetch + extract of the attempt that succeeded)
+    /// takes longer than this many milliseconds. Failed scrapes are not checked.
+    #[serde(default)]
+    slow_scrape_threshold_ms: Option<u64>,
     #[serde(default)]
     extractor: Extractor,
     /// A set of rules
     rules: Vec<Rule>,
+    /// Self-monitoring counters for this target. Populated at scrape time, never deserialized.
+    #[serde(skip)]
+    stats: Mutex<ScrapeStats>,
+}
+
+/// Exporter self-monitoring state, rendered as `scrape_*` meta-metrics alongside a target's rules.
+#[derive(Default, Debug)]
+struct ScrapeStats {
+    /// Whether the most recent scrape succeeded. `None` until the first scrape runs.
+    up: Option<bool>,
+    /// Duration of the most recent successful scrape, in seconds. Failed scrapes leave it unchanged.
+    duration_seconds: f64,
+    /// Unix-millis timestamp of the last successful scrape, if any.
+    last_success_timestamp: Option<u128>,
+    /// Number of samples produced per rule by the most recent successful scrape.
+    samples: HashMap<String, usize>,
+}
+
+/// How failed scrapes should be retried before the run is given up.
+#[derive(Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "generate-schema", derive(JsonSchema))]
+struct Retry {
+    /// Maximum number of attempts (including the first) before the run fails. Defaults to 1, i.e.
+    /// no retries; set it above 1 to actually retry.
+    #[serde(default = "default_max_attempts")]
+    max_attempts: u32,
+    /// Backoff before the first retry, doubled after each subsequent attempt.
+    #[serde(default = "default_initial_backoff_ms")]
+    initial_backoff_ms: u64,
+    /// Upper bound for the backoff between attempts; a jitter of up to 25% is added on top of it.
+    #[serde(default = "default_max_backoff_ms")]
+    max_backoff_ms: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        // Kept in sync with the per-field serde defaults so that an absent `retry:` block and an
+        // empty `retry: {}` behave identically: a single attempt, as before.
+        Self {
+            max_attempts: default_max_attempts(),
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+        }
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    1
+}
+fn default_initial_backoff_ms() -> u64 {
+    250
+}
+fn default_max_backoff_ms() -> u64 {
+    10_000
+}
+
+/// The outcome of a single scrape attempt, classified so the retry loop can decide whether to try again.
+#[derive(Debug)]
+enum ScrapeError {
+    /// Transport-level failure (connection refused, timeout, body read, ...). Always retryable.
+    Transport(anyhow::Error),
+    /// The upstream answered with a non-2xx status. 5xx is retryable, 4xx is not.
+    Status(reqwest::StatusCode),
+    /// The response was fetched but extraction failed. Retrying won't help.
+    Extract(anyhow::Error),
+}
+
+impl ScrapeError {
+    fn retryable(&self) -> bool {
+        match self {
+            ScrapeError::Transport(_) => true,
+            ScrapeError::Status(code) => code.is_server_error(),
+            ScrapeError::Extract(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScrapeError::Transport(e) => write!(f, "{e:#}"),
+            ScrapeError::Status(code) => write!(f, "upstream returned status {code}"),
+            ScrapeError::Extract(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for ScrapeError {}
+
+/// How to authenticate against a target. Saves users from hand-assembling `Authorization` headers.
+#[derive(Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "generate-schema", derive(JsonSchema))]
+#[serde(rename_all = "lowercase")]
+enum Auth {
+    /// `Authorization: Bearer <token>`.
+    Bearer { token: String },
+    /// HTTP Basic authentication.
+    Basic { username: String, password: String },
 }
 
 /// Which engine shall be used to process the response.
@@ -80,6 +201,7 @@ enum Extractor {
     #[default]
     Jq,
     Regex,
+    Lua,
 }
 
 /// How to process to fetched data into metrics.
@@ -100,16 +222,24 @@ struct Rule {
 struct ExtractorStorage {
     jq_filter: Option<jq::JsonFilter>,
     regex: Option<Regex>,
+    lua: Option<LuaScript>,
 }
 impl std::fmt::Debug for ExtractorStorage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ExtractorStorage")
             .field("regex", &self.regex)
             .field("jq_filter", &"(not impl Debug)")
+            .field("lua", &self.lua.as_ref().map(|_| "(compiled script)"))
             .finish_non_exhaustive()
     }
 }
 
+/// A Lua script compiled once during setup, together with the VM it was loaded into.
+struct LuaScript {
+    lua: mlua::Lua,
+    func: mlua::RegistryKey,
+}
+
 /// The type of prometheus metric.
 #[derive(Deserialize, Clone, Default)]
 #[cfg_attr(feature = "generate-schema", derive(JsonSchema))]
@@ -138,10 +268,40 @@ async fn main() {
     let config_file = File::open(config_path)
         .context("Failed to open config file")
         .unwrap();
-    let config: Config = serde_yml::from_reader(config_file)
+    let mut config: Config = serde_yml::from_reader(config_file)
         .context("Failed to Deserialize config")
         .unwrap();
 
+    // Expand ${ENV_VAR} / ${ENV_VAR:-default} references in target URLs and headers so that
+    // secrets can be injected from the environment instead of being committed to the config.
+    let targets = Arc::get_mut(&mut config.targets)
+        .expect("targets Arc is uniquely held right after deserialization");
+    for target in targets.iter_mut() {
+        target.url = expand_env(&target.url)
+            .with_context(|| format!("expanding url of target '{}'", target.name))
+            .unwrap();
+        for value in target.headers.values_mut() {
+            *value = expand_env(value)
+                .with_context(|| format!("expanding headers of target '{}'", target.name))
+                .unwrap();
+        }
+        let name = target.name.clone();
+        if let Some(auth) = target.auth.as_mut() {
+            let expand = |field: &str, value: &str| {
+                expand_env(value)
+                    .with_context(|| format!("expanding {field} of target '{name}'"))
+                    .unwrap()
+            };
+            match auth {
+                Auth::Bearer { token } => *token = expand("auth token", token),
+                Auth::Basic { username, password } => {
+                    *username = expand("auth username", username);
+                    *password = expand("auth password", password);
+                }
+            }
+        }
+    }
+
     let subscriber = FmtSubscriber::builder()
         .with_max_level(
             Level::from_str(&config.log_level)
@@ -153,10 +313,18 @@ async fn main() {
         target.setup().await.unwrap()
     }
 
-    let listener = tokio::net::TcpListener::bind(&config.address)
-        .await
-        .with_context(|| format!("binding to {}", config.address))
-        .unwrap();
+    // Load the TLS material up front so a bad cert/key fails fast instead of at first scrape.
+    let tls_config = match &config.tls {
+        Some(tls) => Some(
+            axum_server::tls_rustls::RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+                .await
+                .with_context(|| {
+                    format!("loading TLS cert '{}' / key '{}'", tls.cert_path, tls.key_path)
+                })
+                .unwrap(),
+        ),
+        None => None,
+    };
 
     if config.scrape_on_startup {
         info!("Initial Scraping of {} targets", config.targets.len());
@@ -216,19 +384,91 @@ async fn main() {
             move || serve_metrics(targets)
         }),
     );
-    axum::serve(listener, app)
-        .with_graceful_shutdown(async {
-            tokio::signal::ctrl_c()
+
+    match tls_config {
+        Some(tls_config) => {
+            // Resolve the same way the plain-HTTP path does via TcpListener, so a hostname like
+            // `localhost:3000` works whether or not `tls` is set.
+            let addr: std::net::SocketAddr = tokio::net::lookup_host(&config.address)
                 .await
-                .expect("failed to install Ctrl+C handler")
-        })
-        .await
-        .unwrap()
+                .with_context(|| format!("resolving address {}", config.address))
+                .unwrap()
+                .next()
+                .with_context(|| format!("address {} resolved to nothing", config.address))
+                .unwrap();
+            info!("Serving /metrics over HTTPS on {addr}");
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    tokio::signal::ctrl_c()
+                        .await
+                        .expect("failed to install Ctrl+C handler");
+                    handle.graceful_shutdown(None);
+                }
+            });
+            axum_server::bind_rustls(addr, tls_config)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await
+                .unwrap()
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(&config.address)
+                .await
+                .with_context(|| format!("binding to {}", config.address))
+                .unwrap();
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async {
+                    tokio::signal::ctrl_c()
+                        .await
+                        .expect("failed to install Ctrl+C handler")
+                })
+                .await
+                .unwrap()
+        }
+    }
 }
 
 async fn serve_metrics(targets: Arc<Vec<Target>>) -> impl axum::response::IntoResponse {
     let mut lines = vec![];
 
+    lines.push("# TYPE scrape_up gauge".to_string());
+    lines.push("# TYPE scrape_duration_seconds gauge".to_string());
+    lines.push("# TYPE scrape_last_success_timestamp gauge".to_string());
+    lines.push("# TYPE scrape_samples gauge".to_string());
+    for target in targets.iter() {
+        let stats = target.stats.lock().await;
+        // Skip a target that has never been scraped yet (e.g. right after startup, before its
+        // first cron tick): reporting `scrape_up 0` there would fire false down-alerts. Once a
+        // scrape has run, `scrape_up` and `scrape_duration_seconds` are always rendered even when
+        // rules produced nothing; the timestamp and sample series appear after a successful scrape.
+        let Some(up) = stats.up else {
+            continue;
+        };
+        let label = format!("{{target=\"{}\"}}", sanitize_for_prometheus(&target.name));
+        lines.push(format!("scrape_up{label} {}", if up { 1 } else { 0 }));
+        lines.push(format!(
+            "scrape_duration_seconds{label} {}",
+            stats.duration_seconds
+        ));
+        if let Some(ts) = stats.last_success_timestamp {
+            lines.push(format!(
+                "scrape_last_success_timestamp{label} {}",
+                ts as f64 / 1000.0
+            ));
+        }
+        for (rule, count) in &stats.samples {
+            lines.push(format!(
+                "scrape_samples{{target=\"{}\",rule=\"{}\"}} {count}",
+                sanitize_for_prometheus(&target.name),
+                sanitize_for_prometheus(rule)
+            ));
+        }
+        drop(stats);
+        lines.push(String::default());
+    }
+
     for target in targets.iter() {
         lines.push(format!(
             "################### {} ###################\n",
@@ -260,6 +500,120 @@ async fn serve_metrics(targets: Arc<Vec<Target>>) -> impl axum::response::IntoRe
 }
 
 async fn try_scrape_target(target: &Target) -> Result<()> {
+    let result = scrape_with_retries(target).await;
+
+    // Only the duration of the actual fetch+extract is recorded, not the retry backoff sleeps.
+    if let Ok((elapsed, _)) = &result {
+        if let Some(threshold) = target.slow_scrape_threshold_ms {
+            if elapsed.as_millis() as u64 > threshold {
+                warn!(
+                    target = target.name,
+                    duration_ms = elapsed.as_millis() as u64,
+                    threshold_ms = threshold,
+                    "Scrape exceeded slow-scrape threshold"
+                );
+            }
+        }
+    }
+
+    let mut stats = target.stats.lock().await;
+    stats.up = Some(result.is_ok());
+    if let Ok((elapsed, samples)) = &result {
+        stats.duration_seconds = elapsed.as_secs_f64();
+        stats.last_success_timestamp = Some(now_millis());
+        stats.samples = samples.clone();
+    }
+
+    result.map(|_| ())
+}
+
+/// Runs a scrape with retries, returning the duration of the successful `scrape_once` (excluding
+/// backoff) and the number of samples each rule produced in that extraction.
+async fn scrape_with_retries(
+    target: &Target,
+) -> Result<(std::time::Duration, HashMap<String, usize>)> {
+    let retry = target.retry.clone().unwrap_or_default();
+    let mut attempt = 1;
+    loop {
+        let started = std::time::Instant::now();
+        match scrape_once(target).await {
+            Ok(samples) => return Ok((started.elapsed(), samples)),
+            Err(e) => {
+                if !e.retryable() || attempt >= retry.max_attempts {
+                    return Err(anyhow!(e));
+                }
+                let backoff = min(
+                    retry.max_backoff_ms,
+                    retry
+                        .initial_backoff_ms
+                        .saturating_mul(1u64 << (attempt - 1).min(63)),
+                );
+                let backoff = backoff + backoff_jitter(backoff);
+                warn!(
+                    target = target.name,
+                    attempt,
+                    max_attempts = retry.max_attempts,
+                    backoff_ms = backoff,
+                    "Scrape failed, retrying: {}",
+                    e
+                );
+                tokio::time::sleep(std::time::Duration::from_millis(backoff)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+static ENV_VAR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)(?::-([^}]*))?\}").unwrap());
+
+/// Replace every `${VAR}` / `${VAR:-default}` occurrence in `input` with the corresponding
+/// environment variable, falling back to the default when given and erroring when a referenced
+/// variable is unset and has no default.
+fn expand_env(input: &str) -> Result<String> {
+    let mut out = String::with_capacity(input.len());
+    let mut last = 0;
+    for caps in ENV_VAR.captures_iter(input) {
+        let whole = caps.get(0).unwrap();
+        out.push_str(&input[last..whole.start()]);
+        let name = caps.get(1).unwrap().as_str();
+        let value = match std::env::var(name) {
+            Ok(v) => v,
+            Err(_) => caps
+                .get(2)
+                .map(|d| d.as_str().to_string())
+                .ok_or_else(|| anyhow!("environment variable '{name}' is not set"))?,
+        };
+        out.push_str(&value);
+        last = whole.end();
+    }
+    out.push_str(&input[last..]);
+    Ok(out)
+}
+
+/// Current Unix time in milliseconds.
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before UNIX_EPOCH??")
+        .as_millis()
+}
+
+/// Deterministic-ish jitter of up to 25% of the backoff, to avoid synchronised retries across targets.
+fn backoff_jitter(backoff: u64) -> u64 {
+    if backoff == 0 {
+        return 0;
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    nanos % (backoff / 4 + 1)
+}
+
+async fn scrape_once(
+    target: &Target,
+) -> std::result::Result<HashMap<String, usize>, ScrapeError> {
     let mut builder = CLIENT.get(&target.url);
 
     if !target.headers.contains_key("User-Agent") {
@@ -274,10 +628,20 @@ async fn try_scrape_target(target: &Target) -> Result<()> {
         );
     }
 
+    match &target.auth {
+        Some(Auth::Bearer { token }) => builder = builder.bearer_auth(token),
+        Some(Auth::Basic { username, password }) => {
+            builder = builder.basic_auth(username, Some(password))
+        }
+        None => {}
+    }
+
     for (k, v) in &target.headers {
         builder = builder.header(k, v)
     }
-    let request = builder.build().with_context(|| "building request")?;
+    let request = builder
+        .build()
+        .map_err(|e| ScrapeError::Transport(anyhow!(e).context("building request")))?;
     debug!(
         target = target.name,
         url = request.url().as_str(),
@@ -286,15 +650,18 @@ async fn try_scrape_target(target: &Target) -> Result<()> {
     let response = CLIENT
         .execute(request)
         .await
-        .with_context(|| "requesting")?
-        .error_for_status()
-        .with_context(|| "status code")?
+        .map_err(|e| ScrapeError::Transport(anyhow!(e).context("requesting")))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(ScrapeError::Status(status));
+    }
+    let body = response
         .text()
         .await
-        .with_context(|| "parsing response as string")?;
+        .map_err(|e| ScrapeError::Transport(anyhow!(e).context("parsing response as string")))?;
 
-    target.extract(response).await?;
-    Ok(())
+    let samples = target.extract(body).await.map_err(ScrapeError::Extract)?;
+    Ok(samples)
 }
 
 impl Target {
@@ -313,12 +680,25 @@ impl Target {
                         Regex::new(&rule.extract).with_context(|| "Failed to compile regex")?;
                     rule.extractor_storage.lock().await.regex = Some(regex);
                 }
+                Extractor::Lua => {
+                    let lua = mlua::Lua::new();
+                    let func = lua
+                        .load(&rule.extract)
+                        .set_name(&rule.name)
+                        .into_function()
+                        .with_context(|| "Failed to compile Lua script")?;
+                    let func = lua
+                        .create_registry_value(func)
+                        .with_context(|| "Failed to store Lua script")?;
+                    rule.extractor_storage.lock().await.lua = Some(LuaScript { lua, func });
+                }
             }
         }
         Ok(())
     }
-    async fn extract(&self, text: String) -> Result<()> {
+    async fn extract(&self, text: String) -> Result<HashMap<String, usize>> {
         debug!(target = self.name, "Extracting from response");
+        let mut samples = HashMap::new();
         for rule in &self.rules {
             let mut to_save = vec![];
             match self.extractor {
@@ -422,12 +802,64 @@ impl Target {
                         Metric::new(&rule.name, num).insert(&mut to_save).await;
                     }
                 }
+
+                Extractor::Lua => {
+                    debug!(target = self.name, rule = rule.name, "Processing with Lua");
+                    // Evaluate the script and build the metrics inside a block so that every mlua
+                    // handle (the guard, `func`, `json`, `produced`) is dropped before the await
+                    // loop below. mlua's types are not `Send` without the `send` feature, and the
+                    // scrape future must be `Send` for `Job::new_async`, so none may cross an await.
+                    let parsed: Vec<Metric> = {
+                        use mlua::LuaSerdeExt;
+                        let lock = rule.extractor_storage.lock().await;
+                        let script = lock.lua.as_ref().unwrap();
+                        let func: mlua::Function = script
+                            .lua
+                            .registry_value(&script.func)
+                            .with_context(|| "Failed to load compiled Lua script")?;
+                        // Hand the script the raw body plus, when the body is JSON, a parsed Lua table.
+                        let json = match serde_json::from_str::<serde_json::Value>(&text) {
+                            Ok(value) => script
+                                .lua
+                                .to_value(&value)
+                                .with_context(|| "converting JSON response to a Lua value")?,
+                            Err(_) => mlua::Value::Nil,
+                        };
+                        let produced: mlua::Table = func
+                            .call((text.clone(), json))
+                            .map_err(|e| anyhow!("Lua Error: {e}"))?;
+                        let mut parsed = vec![];
+                        for metric in produced.sequence_values::<mlua::Table>() {
+                            let metric = metric.map_err(|e| anyhow!("Lua Error: {e}"))?;
+                            let name: String =
+                                metric.get("name").unwrap_or_else(|_| rule.name.clone());
+                            let value: f64 = metric.get("value").map_err(|e| {
+                                anyhow!("Lua metric is missing a numeric 'value': {e}")
+                            })?;
+                            let mut result = Metric::new(name, value);
+                            if let Ok(labels) = metric.get::<mlua::Table>("labels") {
+                                for pair in labels.pairs::<String, mlua::Value>() {
+                                    let (k, v) = pair.map_err(|e| anyhow!("Lua Error: {e}"))?;
+                                    result = result.with_label(k, lua_value_to_string(&v));
+                                }
+                            }
+                            parsed.push(result);
+                        }
+                        parsed
+                    };
+                    for metric in parsed {
+                        metric.insert(&mut to_save).await;
+                    }
+                }
             }
+            // Report what this extraction actually produced, even when it's zero, so that after a
+            // successful scrape the `scrape_samples` metric never reflects a previous run's count.
+            samples.insert(rule.name.clone(), to_save.len());
             if !to_save.is_empty() {
                 *rule.results.lock().await = to_save;
             }
         }
-        Ok(())
+        Ok(samples)
     }
 }
 
@@ -437,12 +869,7 @@ impl Metric {
         N: Into<String>,
         V: Into<f64>,
     {
-        let timestamp = Some(
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("system time before UNIX_EPOCH??")
-                .as_millis(),
-        );
+        let timestamp = Some(now_millis());
         Self {
             name: name.into(),
             value: value.into(),
@@ -499,6 +926,19 @@ impl Metric {
     }
 }
 
+/// Render a Lua label value as a string. Numbers and booleans are stringified; anything else
+/// falls back to its `Debug` form (empty for nil).
+fn lua_value_to_string(value: &mlua::Value) -> String {
+    match value {
+        mlua::Value::String(s) => s.to_string_lossy().to_string(),
+        mlua::Value::Integer(i) => i.to_string(),
+        mlua::Value::Number(n) => n.to_string(),
+        mlua::Value::Boolean(b) => b.to_string(),
+        mlua::Value::Nil => String::new(),
+        other => format!("{other:?}"),
+    }
+}
+
 fn sanitize_for_prometheus(name: &str) -> String {
     name.chars()
         .map(|c| {
@@ -510,3 +950,35 @@ fn sanitize_for_prometheus(name: &str) -> String {
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::expand_env;
+
+    #[test]
+    fn expands_set_variable() {
+        std::env::set_var("PHE_TEST_TOKEN", "secret");
+        assert_eq!(
+            expand_env("Bearer ${PHE_TEST_TOKEN}").unwrap(),
+            "Bearer secret"
+        );
+    }
+
+    #[test]
+    fn uses_default_when_unset() {
+        assert_eq!(
+            expand_env("${PHE_TEST_UNSET:-fallback}").unwrap(),
+            "fallback"
+        );
+    }
+
+    #[test]
+    fn empty_default_yields_empty_string() {
+        assert_eq!(expand_env("x${PHE_TEST_EMPTY:-}y").unwrap(), "xy");
+    }
+
+    #[test]
+    fn errors_when_unset_without_default() {
+        assert!(expand_env("${PHE_TEST_MISSING}").is_err());
+    }
+}